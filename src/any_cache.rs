@@ -0,0 +1,108 @@
+use std::any::{Any, TypeId};
+use std::hash::{BuildHasherDefault, Hasher};
+
+use crate::CacheMap;
+
+/// A `Hasher` specialised for `TypeId` keys.
+///
+/// `TypeId`'s `Hash` impl writes a single, already well-distributed `u64`, so there is no
+/// further mixing to do: we just store that `u64` and hand it back in `finish`.
+#[derive(Default)]
+pub struct TypeIdHasher(u64);
+
+impl Hasher for TypeIdHasher {
+    fn write(&mut self, _bytes: &[u8]) {
+        unreachable!("TypeIdHasher only supports the u64 written by TypeId's Hash impl")
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// An insert-only map for caching at most one value per concrete type.
+///
+/// This is useful for lazy singletons and per-type resource handles: the first call to
+/// [`cache`](AnyCacheMap::cache) for a given `T` runs the closure and stores the result, and
+/// every later call for that same `T` returns the same reference.
+///
+/// # Example
+///
+/// ```
+/// use cachemap2::AnyCacheMap;
+///
+/// let m = AnyCacheMap::new();
+///
+/// let fst: &u32 = m.cache(|| 5u32);
+/// let snd: &u32 = m.cache(|| 7u32);
+///
+/// assert_eq!(*fst, *snd);
+/// assert_eq!(*fst, 5u32);
+/// ```
+pub struct AnyCacheMap {
+    inner: CacheMap<TypeId, Box<dyn Any + Send>, BuildHasherDefault<TypeIdHasher>>,
+}
+
+impl Default for AnyCacheMap {
+    fn default() -> Self {
+        AnyCacheMap {
+            inner: CacheMap::with_hasher(Default::default()),
+        }
+    }
+}
+
+impl AnyCacheMap {
+    /// Creates a new AnyCacheMap
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Fetch the value cached for the type `T`, or run the provided function to insert one.
+    pub fn cache<T: Any + Send, F: FnOnce() -> T>(&self, f: F) -> &T {
+        let boxed = self
+            .inner
+            .cache(TypeId::of::<T>(), || Box::new(f()) as Box<dyn Any + Send>);
+        boxed
+            .downcast_ref()
+            .expect("AnyCacheMap stored a value under the wrong TypeId")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_insert() {
+        let m = AnyCacheMap::new();
+
+        let a = m.cache(|| 21u32);
+        assert_eq!(21, *a);
+    }
+
+    #[test]
+    fn double_insert() {
+        let m = AnyCacheMap::new();
+
+        let a = m.cache(|| 5u32);
+        let b = m.cache(|| 7u32);
+
+        assert_eq!(*a, *b);
+        assert_eq!(5, *a);
+    }
+
+    #[test]
+    fn distinct_types() {
+        let m = AnyCacheMap::new();
+
+        let a: &u32 = m.cache(|| 5u32);
+        let b: &bool = m.cache(|| true);
+
+        assert_eq!(5, *a);
+        assert!(*b);
+    }
+}
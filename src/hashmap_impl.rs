@@ -1,9 +1,10 @@
-use std::hash::Hash;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
 
 #[cfg(not(feature = "abi_stable"))]
 mod basic_impl {
     pub type BoxImpl<T> = Box<T>;
-    pub type HashMapImpl<K, V> = std::collections::HashMap<K, V>;
+    pub type HashMapImpl<K, V, S> = std::collections::HashMap<K, V, S>;
     pub type MutexImpl<T> = std::sync::Mutex<T>;
     pub type MutexGuardImpl<'a, T> = std::sync::MutexGuard<'a, T>;
     pub type IterImpl<'a, K, V> = std::collections::hash_map::Iter<'a, K, V>;
@@ -32,7 +33,7 @@ mod abi_stable_impl {
         std_types::{RBox, RHashMap},
     };
     pub type BoxImpl<T> = RBox<T>;
-    pub type HashMapImpl<K, V> = RHashMap<K, V>;
+    pub type HashMapImpl<K, V, S> = RHashMap<K, V, S>;
     pub type MutexImpl<T> = RMutex<T>;
     pub type MutexGuardImpl<'a, T> =
         abi_stable::external_types::parking_lot::mutex::RMutexGuard<'a, T>;
@@ -58,11 +59,11 @@ use abi_stable_impl::*;
 /// An insert-only map for caching the result of functions
 #[cfg_attr(feature = "abi_stable", derive(abi_stable::StableAbi))]
 #[cfg_attr(feature = "abi_stable", repr(C))]
-pub struct CacheMap<K, V> {
-    inner: MutexImpl<HashMapImpl<K, BoxImpl<V>>>,
+pub struct CacheMap<K, V, S = RandomState> {
+    inner: MutexImpl<HashMapImpl<K, BoxImpl<V>, S>>,
 }
 
-impl<K: Eq + Hash, V> Default for CacheMap<K, V> {
+impl<K: Eq + Hash, V, S: BuildHasher + Default> Default for CacheMap<K, V, S> {
     fn default() -> Self {
         CacheMap {
             inner: MutexImpl::new(Default::default()),
@@ -70,7 +71,9 @@ impl<K: Eq + Hash, V> Default for CacheMap<K, V> {
     }
 }
 
-impl<K: Eq + Hash, V> std::iter::FromIterator<(K, V)> for CacheMap<K, V> {
+impl<K: Eq + Hash, V, S: BuildHasher + Default> std::iter::FromIterator<(K, V)>
+    for CacheMap<K, V, S>
+{
     fn from_iter<T>(iter: T) -> Self
     where
         T: IntoIterator<Item = (K, V)>,
@@ -95,7 +98,7 @@ impl<K, V> Iterator for IntoIter<K, V> {
     }
 }
 
-impl<K, V> IntoIterator for CacheMap<K, V> {
+impl<K, V, S> IntoIterator for CacheMap<K, V, S> {
     type Item = (K, V);
     type IntoIter = IntoIter<K, V>;
 
@@ -104,12 +107,12 @@ impl<K, V> IntoIterator for CacheMap<K, V> {
     }
 }
 
-pub struct Iter<'a, K, V> {
+pub struct Iter<'a, K, V, S> {
     iter: IterImpl<'a, K, BoxImpl<V>>,
-    _guard: MutexGuardImpl<'a, HashMapImpl<K, BoxImpl<V>>>,
+    _guard: MutexGuardImpl<'a, HashMapImpl<K, BoxImpl<V>, S>>,
 }
 
-impl<'a, K, V> Iterator for Iter<'a, K, V> {
+impl<'a, K, V, S> Iterator for Iter<'a, K, V, S> {
     type Item = (&'a K, &'a V);
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -117,9 +120,9 @@ impl<'a, K, V> Iterator for Iter<'a, K, V> {
     }
 }
 
-impl<'a, K, V> IntoIterator for &'a CacheMap<K, V> {
+impl<'a, K, V, S> IntoIterator for &'a CacheMap<K, V, S> {
     type Item = (&'a K, &'a V);
-    type IntoIter = Iter<'a, K, V>;
+    type IntoIter = Iter<'a, K, V, S>;
 
     fn into_iter(self) -> Self::IntoIter {
         let guard = mutex_lock_impl(&self.inner);
@@ -133,7 +136,7 @@ impl<'a, K, V> IntoIterator for &'a CacheMap<K, V> {
     }
 }
 
-impl<K: Eq + Hash, V> CacheMap<K, V> {
+impl<K: Eq + Hash, V, S: BuildHasher> CacheMap<K, V, S> {
     /// Fetch the value associated with the key, or run the provided function to insert one.
     ///
     /// # Example
@@ -169,6 +172,42 @@ impl<K: Eq + Hash, V> CacheMap<K, V> {
         self.cache(key, || Default::default())
     }
 
+    /// Fetch the value associated with the key, or run the provided fallible function to
+    /// insert one.
+    ///
+    /// Unlike [`cache`](CacheMap::cache), `f` is run without holding the map locked, so a slow
+    /// or fallible computation never blocks other callers. If `f` returns `Err`, the key is
+    /// left unset so a later call can retry.
+    pub fn try_cache<E, F: FnOnce() -> Result<V, E>>(&self, key: K, f: F) -> Result<&V, E> {
+        if let Some(v) = self.get(&key) {
+            return Ok(v);
+        }
+
+        let value = f()?;
+
+        let v = std::ptr::NonNull::from(
+            mutex_lock_impl(&self.inner)
+                .entry(key)
+                .or_insert_with(|| BoxImpl::new(value))
+                .as_ref(),
+        );
+        // Safety: We only support adding entries to the hashmap, and as long as a reference is
+        // maintained the value will be present.
+        Ok(unsafe { v.as_ref() })
+    }
+
+    /// Fetch the value associated with the key, if present.
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let v = std::ptr::NonNull::from(mutex_lock_impl(&self.inner).get(key)?.as_ref());
+        // Safety: We only support adding entries to the hashmap, and as long as a reference is
+        // maintained the value will be present.
+        Some(unsafe { v.as_ref() })
+    }
+
     /// Return whether the map contains the given key.
     pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
     where
@@ -181,16 +220,139 @@ impl<K: Eq + Hash, V> CacheMap<K, V> {
     /// Return an iterator over the map.
     ///
     /// This iterator will lock the underlying map until it is dropped.
-    pub fn iter(&self) -> Iter<K, V> {
+    pub fn iter(&self) -> Iter<'_, K, V, S> {
         self.into_iter()
     }
+
+    /// Reserve capacity for at least `additional` more elements.
+    pub fn reserve(&self, additional: usize) {
+        mutex_lock_impl(&self.inner).reserve(additional)
+    }
+}
+
+#[cfg(not(feature = "abi_stable"))]
+impl<K: Eq + Hash, V, S: BuildHasher> CacheMap<K, V, S> {
+    /// Try to reserve capacity for at least `additional` more elements, returning an error
+    /// instead of aborting if the allocation fails.
+    ///
+    /// Not available when built with the `abi_stable` feature, since `RHashMap` has no
+    /// fallible reservation API to surface.
+    pub fn try_reserve(
+        &self,
+        additional: usize,
+    ) -> Result<(), std::collections::TryReserveError> {
+        mutex_lock_impl(&self.inner).try_reserve(additional)
+    }
 }
 
 impl<K: Eq + Hash, V> CacheMap<K, V> {
-    /// Creates a new CacheMap
+    /// Creates a new CacheMap, using the default hasher.
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Creates a new CacheMap with at least the given capacity, using the default hasher.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, Default::default())
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<K: Eq + Hash + Clone + Send, V: Sync, S: BuildHasher> CacheMap<K, V, S> {
+    /// Return a parallel iterator over the map.
+    ///
+    /// Unlike [`iter`](CacheMap::iter), this only locks the underlying map for long enough to
+    /// collect the current entries, so it doesn't block concurrent `cache` calls for the
+    /// duration of the traversal. Keys are cloned out rather than borrowed, since (unlike the
+    /// boxed values) they aren't address-stable across a rehash.
+    pub fn par_iter(&self) -> rayon::vec::IntoIter<(K, &V)> {
+        use rayon::iter::IntoParallelIterator;
+
+        let guard = mutex_lock_impl(&self.inner);
+        let entries: Vec<(K, &V)> = guard
+            .iter()
+            .map(|t| {
+                let k = t.0.clone();
+                // Safety: We only support adding entries to the hashmap, and values are
+                // heap-allocated via BoxImpl, so this reference remains valid for as long as
+                // `self` does, even once `guard` is dropped.
+                let v = unsafe { &*(t.1.as_ref() as *const V) };
+                (k, v)
+            })
+            .collect();
+        drop(guard);
+        entries.into_par_iter()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Eq + Hash + serde::Serialize, V: serde::Serialize, S: BuildHasher> serde::Serialize
+    for CacheMap<K, V, S>
+{
+    fn serialize<Ser: serde::Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        use serde::ser::SerializeMap;
+
+        let guard = mutex_lock_impl(&self.inner);
+        let mut map = serializer.serialize_map(Some(guard.len()))?;
+        for t in guard.iter() {
+            map.serialize_entry(t.0, t.1.as_ref())?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K, V, S> serde::Deserialize<'de> for CacheMap<K, V, S>
+where
+    K: Eq + Hash + serde::Deserialize<'de>,
+    V: serde::Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = std::collections::HashMap::<K, V>::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<K: Eq + Hash + Send, V: Send, S: BuildHasher> CacheMap<K, V, S> {
+    /// Populate the map from a parallel iterator.
+    ///
+    /// Each item is computed and boxed concurrently across the rayon thread pool, then the
+    /// results are inserted under a single short lock. Keys that are already present keep
+    /// their existing value.
+    pub fn par_extend<I>(&self, iter: I)
+    where
+        I: rayon::iter::IntoParallelIterator<Item = (K, V)>,
+    {
+        use rayon::iter::ParallelIterator;
+
+        let items: Vec<(K, BoxImpl<V>)> = iter
+            .into_par_iter()
+            .map(|(k, v)| (k, BoxImpl::new(v)))
+            .collect();
+        let mut map = mutex_lock_impl(&self.inner);
+        for (k, v) in items {
+            map.entry(k).or_insert(v);
+        }
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher> CacheMap<K, V, S> {
+    /// Creates a new CacheMap which will use the given hash builder to hash keys.
+    pub fn with_hasher(hash_builder: S) -> Self {
+        CacheMap {
+            inner: MutexImpl::new(HashMapImpl::with_hasher(hash_builder)),
+        }
+    }
+
+    /// Creates a new CacheMap with at least the given capacity, using the given hash builder
+    /// to hash keys.
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> Self {
+        CacheMap {
+            inner: MutexImpl::new(HashMapImpl::with_capacity_and_hasher(capacity, hash_builder)),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -261,4 +423,111 @@ mod tests {
 
         assert!(expected.is_empty());
     }
+
+    #[test]
+    fn with_capacity() {
+        let m = CacheMap::with_capacity(16);
+
+        let a = m.cache("key", || 21u32);
+        assert_eq!(21, *a);
+    }
+
+    #[test]
+    fn reserve() {
+        let m = CacheMap::new();
+        m.cache("key", || 21u32);
+
+        m.reserve(16);
+        assert!(m.contains_key("key"));
+
+        m.try_reserve(16).expect("reservation should succeed");
+        assert!(m.contains_key("key"));
+    }
+
+    #[test]
+    fn with_hasher() {
+        use std::collections::hash_map::RandomState;
+
+        let m = CacheMap::with_hasher(RandomState::new());
+
+        let a = m.cache("key", || 21u32);
+        assert_eq!(21, *a);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_iter() {
+        use rayon::iter::ParallelIterator;
+        use std::collections::HashMap;
+        use std::iter::FromIterator;
+
+        let m = CacheMap::new();
+        m.cache("a", || 5u32);
+        m.cache("b", || 7u32);
+
+        let mut expected = HashMap::<&'static str, u32>::from_iter([("a", 5u32), ("b", 7u32)]);
+
+        for (k, v) in m.par_iter().collect::<Vec<_>>() {
+            assert!(expected.remove(k).expect("unexpected key") == *v);
+        }
+
+        assert!(expected.is_empty());
+    }
+
+    #[test]
+    fn get() {
+        let m = CacheMap::new();
+        m.cache("key", || 21u32);
+
+        assert_eq!(Some(&21), m.get("key"));
+        assert_eq!(None, m.get("other"));
+    }
+
+    #[test]
+    fn try_cache_err_leaves_slot_empty() {
+        let m = CacheMap::new();
+
+        let err = m.try_cache("key", || Err::<u32, _>("boom"));
+        assert_eq!(Err("boom"), err);
+        assert!(!m.contains_key("key"));
+
+        let ok = m.try_cache("key", || Ok::<_, &str>(21u32));
+        assert_eq!(Ok(&21), ok);
+        assert!(m.contains_key("key"));
+    }
+
+    #[test]
+    fn try_cache_ok_then_cached() {
+        let m = CacheMap::new();
+
+        let a = m.try_cache("key", || Ok::<_, &str>(5u32)).unwrap();
+        let b = m.try_cache("key", || Ok::<_, &str>(7u32)).unwrap();
+
+        assert_eq!(*a, *b);
+        assert_eq!(5, *a);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let m = CacheMap::new();
+        m.cache("a", || 5u32);
+        m.cache("b", || 7u32);
+
+        let json = serde_json::to_string(&m).unwrap();
+        let round_tripped: CacheMap<String, u32> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(5, *round_tripped.cache("a".to_owned(), || 0));
+        assert_eq!(7, *round_tripped.cache("b".to_owned(), || 0));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_extend() {
+        let m = CacheMap::new();
+        m.par_extend(vec![("a", 5u32), ("b", 7u32)]);
+
+        assert_eq!(5, *m.cache("a", || 0));
+        assert_eq!(7, *m.cache("b", || 0));
+    }
 }
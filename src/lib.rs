@@ -9,3 +9,6 @@ mod hashmap_impl;
 
 #[cfg(not(feature = "dashmap"))]
 pub use hashmap_impl::*;
+
+mod any_cache;
+pub use any_cache::AnyCacheMap;